@@ -1,52 +1,134 @@
-use std::{fs::{read_dir, DirEntry, File, ReadDir}, io::Read, os::unix::ffi::OsStrExt, path::Path};
+use std::{collections::HashSet, fs::{metadata, read_dir, read_link, File, OpenOptions, ReadDir}, io::{self, Read, Seek, SeekFrom, Write}, os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd}, path::{Path, PathBuf}};
 
-use clap::{command, Parser};
+use clap::Parser;
+use memchr::memmem;
 
+/// Everything that can go wrong while probing or rewriting a partition. The
+/// `Open`, `ReadDir` and `FatOpen` variants capture the operation and the
+/// offending path so a failure deep inside a `scan` over dozens of block
+/// devices still names what broke; `FatEntry` names only the operation, and
+/// `IOError` carries a bare I/O error with neither.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
-    IOError (String),
+    Open { path: PathBuf, source: io::Error },
+    ReadDir { path: PathBuf, source: io::Error },
+    FatOpen { path: PathBuf, source: io::Error },
+    FatEntry { source: io::Error },
+    Ext { message: String },
+    IOError(io::Error),
     InvalidSystem,
     IllegalID,
+    Unsupported { message: String },
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-impl From<std::io::Error> for Error {
-    fn from(value: std::io::Error) -> Self {
-        Self::IOError(format!("{value}"))
+impl Error {
+    /// Process exit code for this error, so callers can branch on the outcome.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::InvalidSystem => 2,
+            Error::IllegalID => 3,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Open { path, source } =>
+                write!(f, "failed to open '{}': {}", path.display(), source),
+            Error::ReadDir { path, source } =>
+                write!(f, "failed to read directory '{}': {}", path.display(), source),
+            Error::FatOpen { path, source } =>
+                write!(f, "failed to open FAT filesystem '{}': {}", path.display(), source),
+            Error::FatEntry { source } =>
+                write!(f, "failed to read FAT directory entry: {source}"),
+            Error::Ext { message } =>
+                write!(f, "ext filesystem error: {message}"),
+            Error::IOError(source) => write!(f, "IO error: {source}"),
+            Error::InvalidSystem => write!(f, "no recognized CoreELEC/EmuELEC system found"),
+            Error::IllegalID => write!(f, "illegal partition ID"),
+            Error::Unsupported { message } => write!(f, "unsupported operation: {message}"),
+        }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open { source, .. } |
+            Error::ReadDir { source, .. } |
+            Error::FatOpen { source, .. } |
+            Error::FatEntry { source } |
+            Error::IOError(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::IOError(value)
+    }
+}
+
+/// Attach the offending path to a bare I/O result, turning it into an
+/// [`Error::Open`] that names what was being accessed.
+trait Context<T> {
+    fn context<P: AsRef<Path>>(self, path: P) -> Result<T>;
+}
+
+impl<T> Context<T> for std::result::Result<T, io::Error> {
+    fn context<P: AsRef<Path>>(self, path: P) -> Result<T> {
+        self.map_err(|source| Error::Open {
+            path: path.as_ref().to_path_buf(), source
+        })
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 type FATFS = fatfs::FileSystem<File>;
 type FATDir<'a> = fatfs::Dir<'a, File>;
-type FATEntry<'a> = fatfs::DirEntry<'a, File>;
 type FATFile<'a> = fatfs::File<'a, File>;
 
 fn file_open_checked<P: AsRef<Path>>(path: P) -> Result<File> {
-    File::open(&path).map_err(|e|{
-        eprintln!("Failed to open file '{}': {}", path.as_ref().display(), e);
-        e.into()
+    File::open(&path).map_err(|source| Error::Open {
+        path: path.as_ref().to_path_buf(), source
     })
 }
 
 fn fatfs_open<P: AsRef<Path>>(path: P) -> Result<FATFS> {
     fatfs::FileSystem::new(
         file_open_checked(&path)?, fatfs::FsOptions::new()
-    ).map_err(|e|{
-        eprintln!("Failed to open FAT filesystem '{}': {}", 
-                    path.as_ref().display(), e);
-        e.into()
+    ).map_err(|source| Error::FatOpen {
+        path: path.as_ref().to_path_buf(), source
+    })
+}
+
+fn file_open_rw_checked<P: AsRef<Path>>(path: P) -> Result<File> {
+    OpenOptions::new().read(true).write(true).open(&path).map_err(|source| Error::Open {
+        path: path.as_ref().to_path_buf(), source
+    })
+}
+
+fn fatfs_open_rw<P: AsRef<Path>>(path: P) -> Result<FATFS> {
+    fatfs::FileSystem::new(
+        file_open_rw_checked(&path)?, fatfs::FsOptions::new()
+    ).map_err(|source| Error::FatOpen {
+        path: path.as_ref().to_path_buf(), source
     })
 }
 
 fn read_dir_checked<P: AsRef<Path>>(path: P) -> Result<ReadDir> {
-    read_dir(&path).map_err(|e|{
-        eprintln!("Failed to read dir '{}': {}", path.as_ref().display(), e);
-        e.into()
+    read_dir(&path).map_err(|source| Error::ReadDir {
+        path: path.as_ref().to_path_buf(), source
     })
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 enum SubSystem {
     OfficialCoreELEC,
     OfficialEmuELEC,
@@ -81,6 +163,27 @@ impl SubSystem {
             SubSystem::HybridEmuELEC => b"HybridELEC (EE) on eMMC",
         }
     }
+
+    fn is_official(&self) -> bool {
+        matches!(self, SubSystem::OfficialCoreELEC | SubSystem::OfficialEmuELEC)
+    }
+}
+
+impl std::str::FromStr for SubSystem {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "OfficialCoreELEC" => Ok(SubSystem::OfficialCoreELEC),
+            "OfficialEmuELEC" => Ok(SubSystem::OfficialEmuELEC),
+            "HybridCoreELEC" => Ok(SubSystem::HybridCoreELEC),
+            "HybridEmuELEC" => Ok(SubSystem::HybridEmuELEC),
+            _ => {
+                eprintln!("Illegal subsystem name: {:?}", s);
+                Err(Error::InvalidSystem)
+            }
+        }
+    }
 }
 
 fn check_buffer_cfgload_system(buffer: &[u8]) -> Option<SubSystem> {
@@ -95,74 +198,248 @@ fn check_buffer_cfgload_system(buffer: &[u8]) -> Option<SubSystem> {
     None
 }
 
-fn check_fat_file_cfgload_system(fatfile: &mut FATFile) 
-    -> Result<Option<SubSystem>> 
-{
-    let mut buffer = Vec::new();
-    fatfile.read_to_end(&mut buffer)?;
-    Ok(check_buffer_cfgload_system(&buffer))
+/// Stream `reader` (a `cfgload` file) in fixed-size chunks, scanning for each of
+/// the four `cfgload_flag()` byte patterns with a `memchr::memmem::Finder`
+/// substring matcher, and return the first recognized [`SubSystem`].
+///
+/// Memory stays bounded regardless of `cfgload` size: only one chunk plus a
+/// `max_flag_len - 1` byte carry-over is retained, so a flag straddling a chunk
+/// boundary is still matched.
+fn scan_reader_cfgload_system<R: Read>(mut reader: R) -> Result<Option<SubSystem>> {
+    let finders: Vec<(SubSystem, memmem::Finder<'_>)> = SubSystem::iterator()
+        .map(|subsystem| (*subsystem, memmem::Finder::new(subsystem.cfgload_flag())))
+        .collect();
+    let max_flag_len = SubSystem::iterator()
+        .map(|subsystem| subsystem.cfgload_flag().len())
+        .max()
+        .unwrap_or(0);
+    let carry = max_flag_len.saturating_sub(1);
+
+    const CHUNK: usize = 8 * 1024;
+    let mut buf = [0u8; CHUNK];
+    let mut window: Vec<u8> = Vec::with_capacity(CHUNK + carry);
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break
+        }
+        window.extend_from_slice(&buf[..read]);
+        for (subsystem, finder) in &finders {
+            if finder.find(&window).is_some() {
+                return Ok(Some(*subsystem))
+            }
+        }
+        if window.len() > carry {
+            let consumed = window.len() - carry;
+            window.drain(..consumed);
+        }
+    }
+    Ok(None)
 }
 
-fn check_fat_entry_cfgload_system(cfgload: &FATEntry) 
-    -> Result<Option<SubSystem>> 
-{
-    check_fat_file_cfgload_system(&mut cfgload.to_file())
-}
-
-fn check_fat_dir_system(dir: &FATDir) -> Result<Option<SubSystem>> {
-    // let mut cfgload = false;
-    let mut config_ini = false;
-    let mut device_trees = false;
-    let mut kernel_img = false;
-    let mut system = false;
-    let mut subsystem = None;
-    let mut cfgload = None;
-    for entry in dir.iter() {
-        let entry = entry.map_err(|e|{
-            eprintln!("Failed to read FAT dir entry: {}", e);
-            Error::from(e)
-        })?;
-        match entry.file_name().as_str() {
-            "cfgload" => {
-                cfgload = if entry.is_file() {
-                    Some(entry)
+/// Kind of a root-directory entry, as reported by a [`SystemProbe`] backend
+/// without committing to any particular on-disk layout.
+enum EntryKind {
+    File,
+    Dir,
+}
+
+/// A read-only view of a partition's root directory, abstracting over the
+/// underlying filesystem (FAT for SD/USB media, ext2/ext4 for eMMC installs).
+/// The marker-file and `cfgload` detection logic is expressed once against this
+/// trait so every [`SubSystem`] is reported uniformly regardless of format.
+trait SystemProbe {
+    /// Look up `name` in the root directory and report its kind, or `None` if
+    /// it is absent (or unreadable).
+    fn find_entry(&self, name: &str) -> Option<EntryKind>;
+    /// Open the root-directory `cfgload` for streaming reads. The FAT backend
+    /// streams straight from disk; the ext backend reads the whole file into
+    /// memory first.
+    fn open_cfgload(&self) -> Result<Box<dyn Read + '_>>;
+}
+
+struct FatProbe {
+    fs: FATFS,
+}
+
+impl SystemProbe for FatProbe {
+    fn find_entry(&self, name: &str) -> Option<EntryKind> {
+        for entry in self.fs.root_dir().iter() {
+            let entry = entry.ok()?;
+            if entry.file_name().as_str() == name {
+                return Some(if entry.is_dir() {
+                    EntryKind::Dir
                 } else {
-                    None
-                };
-            },
-            "config.ini" => config_ini = entry.is_file(),
-            "device_trees" => device_trees = entry.is_dir(),
-            "kernel.img" => kernel_img = entry.is_file(),
-            "SYSTEM" => system = entry.is_file(),
-            _ => ()
+                    EntryKind::File
+                })
+            }
         }
+        None
     }
-    if config_ini && device_trees && kernel_img && system {
-        if let Some(cfgload) = cfgload {
-            subsystem = check_fat_entry_cfgload_system(&cfgload)?
-        }
+
+    fn open_cfgload(&self) -> Result<Box<dyn Read + '_>> {
+        let file = self.fs.root_dir().open_file("cfgload")
+            .map_err(|source| Error::FatEntry { source })?;
+        Ok(Box::new(file))
+    }
+}
+
+struct ExtProbe {
+    block: std::cell::RefCell<ext4::SuperBlock<File>>,
+}
+
+impl ExtProbe {
+    fn root_path(name: &str) -> String {
+        format!("/{name}")
+    }
+
+    /// Materialize `name` into a buffer. The `ext4` reader borrows the
+    /// superblock mutably, so — unlike FAT — it can't be streamed out through a
+    /// `'_` reader; `cfgload` is tiny, so this is harmless.
+    fn read_file(&self, name: &str) -> Result<Vec<u8>> {
+        let block = self.block.borrow_mut();
+        let entry = block.resolve_path(&Self::root_path(name)).map_err(|e| Error::Ext {
+            message: format!("failed to resolve '/{name}': {e}")
+        })?;
+        let inode = block.load_inode(entry.inode).map_err(|e| Error::Ext {
+            message: format!("failed to load inode for '/{name}': {e}")
+        })?;
+        let mut reader = block.open(&inode).map_err(|e| Error::Ext {
+            message: format!("failed to open '/{name}': {e}")
+        })?;
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+        Ok(buffer)
     }
-    Ok (subsystem)
-    
 }
 
-fn check_fat_fs_system(fatfs: &FATFS) -> Result<Option<SubSystem>> {
-    check_fat_dir_system(&fatfs.root_dir())
+impl SystemProbe for ExtProbe {
+    fn find_entry(&self, name: &str) -> Option<EntryKind> {
+        let block = self.block.borrow_mut();
+        let entry = block.resolve_path(&Self::root_path(name)).ok()?;
+        Some(match entry.file_type {
+            ext4::FileType::Directory => EntryKind::Dir,
+            ext4::FileType::RegularFile => EntryKind::File,
+            _ => return None,
+        })
+    }
+
+    fn open_cfgload(&self) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(io::Cursor::new(self.read_file("cfgload")?)))
+    }
 }
 
-fn check_file_system(file: File) -> Result<Option<SubSystem>> {
-    check_fat_fs_system(
-        &FATFS::new(file, fatfs::FsOptions::new())?)
+fn check_probe_system(probe: &dyn SystemProbe) -> Result<Option<SubSystem>> {
+    let markers =
+        matches!(probe.find_entry("config.ini"), Some(EntryKind::File)) &&
+        matches!(probe.find_entry("device_trees"), Some(EntryKind::Dir)) &&
+        matches!(probe.find_entry("kernel.img"), Some(EntryKind::File)) &&
+        matches!(probe.find_entry("SYSTEM"), Some(EntryKind::File)) &&
+        matches!(probe.find_entry("cfgload"), Some(EntryKind::File));
+    if markers {
+        scan_reader_cfgload_system(probe.open_cfgload()?)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Return whether the device at `path` carries an ext2/3/4 superblock, by
+/// checking the `0xEF53` magic at offset 1024+56.
+fn is_ext_filesystem<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mut file = file_open_checked(&path)?;
+    file.seek(SeekFrom::Start(1024 + 56))?;
+    let mut magic = [0u8; 2];
+    file.read_exact(&mut magic)?;
+    Ok(u16::from_le_bytes(magic) == 0xEF53)
 }
 
 fn check_path_system<P: AsRef<Path>>(path: P
-) -> Result<Option<SubSystem>> 
+) -> Result<Option<SubSystem>>
+{
+    if let Ok(fs) = fatfs_open(&path) {
+        return check_probe_system(&FatProbe { fs })
+    }
+    if is_ext_filesystem(&path)? {
+        let block = ext4::SuperBlock::new(file_open_checked(&path)?).map_err(|e| Error::Ext {
+            message: format!("failed to open '{}': {e}", path.as_ref().display())
+        })?;
+        return check_probe_system(&ExtProbe { block: std::cell::RefCell::new(block) })
+    }
+    Ok(None)
+}
+
+/// Rewrite the `cfgload` buffer so the currently recognized subsystem flag is
+/// replaced by `target`'s flag. Only touches the buffer when the existing
+/// content already matches one of the four known flags; the recognized flag may
+/// be shorter or longer than the replacement, so the caller must rewrite the
+/// whole file and truncate afterwards.
+fn replace_buffer_cfgload_flag(buffer: &[u8], target: SubSystem) -> Result<Vec<u8>> {
+    let current = match check_buffer_cfgload_system(buffer) {
+        Some(current) => current,
+        None => {
+            eprintln!("Existing cfgload does not match any known flag, refusing \
+                        to rewrite");
+            return Err(Error::InvalidSystem)
+        }
+    };
+    if current.is_official() {
+        eprintln!("Existing cfgload belongs to an official subsystem ({}), \
+                    refusing to rewrite", current.as_str());
+        return Err(Error::InvalidSystem)
+    }
+    let from = current.cfgload_flag();
+    let to = target.cfgload_flag();
+    let pos = buffer.windows(from.len())
+        .position(|window|window == from)
+        .expect("recognized flag must be present");
+    let mut rewritten = Vec::with_capacity(buffer.len() + to.len());
+    rewritten.extend_from_slice(&buffer[..pos]);
+    rewritten.extend_from_slice(to);
+    rewritten.extend_from_slice(&buffer[pos + from.len()..]);
+    Ok(rewritten)
+}
+
+fn set_fat_file_cfgload_system(fatfile: &mut FATFile, target: SubSystem)
+    -> Result<()>
 {
-    check_file_system(file_open_checked(path)?)
+    let mut buffer = Vec::new();
+    fatfile.read_to_end(&mut buffer)?;
+    let rewritten = replace_buffer_cfgload_flag(&buffer, target)?;
+    fatfile.seek(SeekFrom::Start(0))?;
+    fatfile.write_all(&rewritten)?;
+    fatfile.truncate()?;
+    fatfile.flush()?;
+    Ok(())
 }
 
-fn check_dir_entry_system(entry: DirEntry) -> Result<Option<SubSystem>> {
-    check_path_system(&entry.path())
+fn set_fat_dir_system(dir: &FATDir, target: SubSystem) -> Result<()> {
+    let mut cfgload = dir.open_file("cfgload")
+        .map_err(|source| Error::FatEntry { source })?;
+    set_fat_file_cfgload_system(&mut cfgload, target)
+}
+
+/// Switch the partition at `path` to `target` by rewriting its `cfgload`.
+///
+/// Detection recognizes Hybrid systems on both FAT and ext2/3/4 (see
+/// [`check_path_system`]), but the rewrite only supports FAT: the ext backend
+/// is read-only. An ext Hybrid partition is therefore rejected up front with a
+/// clear message rather than failing with an opaque `FatOpen` error.
+fn set_path_system<P: AsRef<Path>>(path: P, target: SubSystem) -> Result<()> {
+    if target.is_official() {
+        eprintln!("Refusing to switch partition to an official subsystem: {}",
+                    target.as_str());
+        return Err(Error::InvalidSystem)
+    }
+    if is_ext_filesystem(&path)? {
+        return Err(Error::Unsupported {
+            message: format!(
+                "set-system only supports FAT partitions; '{}' is ext2/3/4",
+                path.as_ref().display()),
+        })
+    }
+    let fatfs = fatfs_open_rw(&path)?;
+    let result = set_fat_dir_system(&fatfs.root_dir(), target);
+    result
 }
 
 fn id_from_bytes(bytes: &[u8]) -> Option<usize> {
@@ -191,41 +468,180 @@ fn id_from_bytes(bytes: &[u8]) -> Option<usize> {
     Some(id)
 }
 
-/// Scan /dev/block/[prefix] dev files, and report which 
-fn scan(prefix: &str) -> Result<()> {
-    let prefix = prefix.as_bytes();
-    let len_prefix = prefix.len();
-    let mut ce: usize = 0;
-    let mut ee: usize = 0;
+/// `_IOR(0x12, 114, size_t)` — fetch a block device's size in bytes, as Magisk
+/// does. Valid on 64-bit Linux where `size_t` is 8 bytes.
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// Physical size of the device/file behind `file`, in bytes. Tries the
+/// `BLKGETSIZE64` ioctl first (block devices) and falls back to seeking to the
+/// end (regular files, which ignore the ioctl).
+fn device_size(file: &File) -> Result<u64> {
+    let mut size: u64 = 0;
+    let ret = unsafe {
+        libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size as *mut u64)
+    };
+    if ret == 0 {
+        return Ok(size)
+    }
+    Ok((&*file).seek(SeekFrom::End(0))?)
+}
+
+/// Canonical path of the device behind `file`, resolved Magisk-style through
+/// `/proc/self/fd/<n>`, so symlinks and bind mounts report their real node.
+fn fd_path(file: &File) -> Result<PathBuf> {
+    let link = format!("/proc/self/fd/{}", file.as_raw_fd());
+    read_link(&link).context(&link)
+}
+
+/// Parse the trailing run of digits in a device node name as its numeric ID,
+/// e.g. `mmcblk0p7` → `7`; `0` when the name has no trailing digits. A
+/// single-path `check-system` target need not be a numbered partition node
+/// (it may be a plain FAT/ext image), so a missing ID is not an error here —
+/// [`Error::IllegalID`] is reserved for the `scan` node-parsing path.
+fn node_id(path: &Path) -> usize {
+    let name = match path.file_name() {
+        Some(name) => name.as_bytes(),
+        None => return 0,
+    };
+    let start = name.iter()
+        .rposition(|b| ! b.is_ascii_digit())
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    id_from_bytes(&name[start..]).unwrap_or(0)
+}
+
+/// Output format selected with `--format`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// A matching partition, as reported in `--format json` output.
+struct PartitionInfo {
+    device: PathBuf,
+    id: usize,
+    system: SubSystem,
+    size: u64,
+}
+
+impl PartitionInfo {
+    /// Gather the metadata for the already-detected `system` at `path`.
+    fn gather<P: AsRef<Path>>(path: P, id: usize, system: SubSystem)
+        -> Result<Self>
+    {
+        let file = file_open_checked(&path)?;
+        let device = fd_path(&file).unwrap_or_else(|_| path.as_ref().to_path_buf());
+        let size = device_size(&file)?;
+        Ok(PartitionInfo { device, id, system, size })
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"device\":\"{}\",\"id\":{},\"system\":\"{}\",\"size\":{}}}",
+            json_escape(&self.device.to_string_lossy()),
+            self.id, self.system.as_str(), self.size)
+    }
+}
+
+/// Escape a string for embedding in a JSON double-quoted value. Device node
+/// paths only ever need `"` and `\` escaped in practice.
+fn json_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn print_partitions_json(partitions: &[PartitionInfo]) {
+    let body = partitions.iter()
+        .map(PartitionInfo::to_json)
+        .collect::<Vec<_>>()
+        .join(",");
+    println!("[{body}]");
+}
+
+/// Scan `/dev/block` for partitions whose name starts with one of `prefixes`,
+/// optionally constrained to the concrete nodes in `only` and never touching
+/// those in `exclude`, and report the minimum CE/EE Hybrid partition IDs.
+///
+/// Each candidate is `stat`ed for its underlying device (`st_rdev`) so that two
+/// names resolving to the same device — e.g. a symlink and its target — are
+/// only probed once.
+///
+/// In `Format::Text` the minimum CE/EE Hybrid IDs are printed as `"{ce} {ee}"`;
+/// in `Format::Json` every matching partition is listed with its metadata.
+fn scan(prefixes: &[String], only: &[String], exclude: &[String], format: Format)
+    -> Result<()>
+{
+    let mut seen: HashSet<u64> = HashSet::new();
+    let mut partitions: Vec<PartitionInfo> = Vec::new();
     for entry in read_dir_checked("/dev/block")?
     {
-        let entry = entry.map_err(|e|{
-            eprintln!("Failed to read dir entry under '/dev/block': {}", e);
-            Error::from(e)
+        let entry = entry.map_err(|source| Error::ReadDir {
+            path: PathBuf::from("/dev/block"), source
         })?;
-        let name = entry.file_name();
-        let name = name.as_bytes();
-        if ! name.starts_with(prefix) {
+        let path = entry.path();
+        let path_str = path.to_string_lossy();
+        if exclude.iter().any(|node|node == path_str.as_ref()) {
             continue;
         }
-        let id = match id_from_bytes(&name[len_prefix..]) {
-            Some(id) => id,
+        if ! only.is_empty() && ! only.iter().any(|node|node == path_str.as_ref()) {
+            continue;
+        }
+        let name = entry.file_name();
+        let name = name.as_bytes();
+        let matched = prefixes.iter().find(|prefix| name.starts_with(prefix.as_bytes()));
+        let prefix = match matched {
+            Some(prefix) => prefix.as_bytes(),
             None => continue,
         };
+        let id = match id_from_bytes(&name[prefix.len()..]) {
+            Some(id) => id,
+            None => {
+                eprintln!("{}: {}", Error::IllegalID, path.display());
+                continue
+            }
+        };
         if id == 0 {
             continue
         }
-        match check_dir_entry_system(entry) {
-            Ok(Some(SubSystem::HybridCoreELEC)) => if ce == 0 || ce > id {
-                ce = id
-            },
-            Ok(Some(SubSystem::HybridEmuELEC)) => if ee == 0 || ee > id {
-                ee = id
-            },
-            _ => (),
+        let rdev = match metadata(&path) {
+            Ok(meta) => meta.rdev(),
+            Err(e) => {
+                eprintln!("Failed to stat candidate '{}': {}", path.display(), e);
+                continue
+            }
+        };
+        if ! seen.insert(rdev) {
+            continue
+        }
+        if let Ok(Some(system @ (SubSystem::HybridCoreELEC | SubSystem::HybridEmuELEC)))
+            = check_path_system(&path)
+        {
+            match PartitionInfo::gather(&path, id, system) {
+                Ok(info) => partitions.push(info),
+                Err(e) => {
+                    eprintln!("Skipping '{}': {e}", path.display());
+                    continue
+                }
+            }
         }
     }
-    println!("{ce} {ee}");
+    match format {
+        Format::Text => {
+            let mut ce: usize = 0;
+            let mut ee: usize = 0;
+            for partition in &partitions {
+                match partition.system {
+                    SubSystem::HybridCoreELEC if ce == 0 || ce > partition.id =>
+                        ce = partition.id,
+                    SubSystem::HybridEmuELEC if ee == 0 || ee > partition.id =>
+                        ee = partition.id,
+                    _ => (),
+                }
+            }
+            println!("{ce} {ee}");
+        },
+        Format::Json => print_partitions_json(&partitions),
+    }
     Ok(())
 }
 
@@ -242,23 +658,72 @@ enum Action {
         /// in which `cfgload` is specially for a subsystem
         path: String
     },
+    /// Switch the active boot target of a Hybrid partition by rewriting its
+    /// `cfgload` flag in place. Only partitions whose current `cfgload` already
+    /// matches a known flag are touched, and `target` must be a Hybrid system.
+    SetSystem {
+        /// Path of file/dev to rewrite, containing a writable FAT fs with a
+        /// recognized `cfgload`
+        path: String,
+        /// Target subsystem: `HybridCoreELEC` or `HybridEmuELEC`
+        target: SubSystem,
+    },
+    /// Scan `/dev/block` for Hybrid CE/EE partitions and print the minimum
+    /// `"{ce} {ee}"` IDs, or with `--format json` list every matching
+    /// partition with its metadata. Candidates are matched by name prefix, optionally
+    /// constrained to an allow/deny set of concrete device nodes, and
+    /// deduplicated by underlying device.
+    Scan {
+        /// Name prefix to match under `/dev/block` (repeatable)
+        #[arg(long, default_value = "mmcblk0p")]
+        prefix: Vec<String>,
+        /// Only probe these concrete device nodes (repeatable)
+        #[arg(long)]
+        only: Vec<String>,
+        /// Never probe these concrete device nodes (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
 }
 
 #[derive(Parser, Debug)]
 #[command(version)]
 struct Arg {
     #[command(subcommand)]
-    action: Action
+    action: Action,
+    /// Output format: human-readable `text` or machine-readable `json`
+    #[arg(long, value_enum, default_value_t = Format::Text, global = true)]
+    format: Format,
 }
 
-fn main() -> Result<()> {
-    let arg = Arg::parse();
-    match arg.action {
-        Action::CheckSystem { path } => if let Some(system) = check_path_system(&path)? {
-            println!("{}", system.as_str())
-        } else {
-            return Err(Error::InvalidSystem)
-        },
+fn check_system(path: &str, format: Format) -> Result<()> {
+    let system = match check_path_system(path)? {
+        Some(system) => system,
+        None => return Err(Error::InvalidSystem),
+    };
+    match format {
+        Format::Text => println!("{}", system.as_str()),
+        Format::Json => {
+            let info = PartitionInfo::gather(path, node_id(Path::new(path)), system)?;
+            print_partitions_json(&[info]);
+        }
+    }
+    Ok(())
+}
+
+fn run(action: Action, format: Format) -> Result<()> {
+    match action {
+        Action::CheckSystem { path } => check_system(&path, format)?,
+        Action::SetSystem { path, target } => set_path_system(&path, target)?,
+        Action::Scan { prefix, only, exclude } => scan(&prefix, &only, &exclude, format)?,
     }
     Ok(())
+}
+
+fn main() {
+    let arg = Arg::parse();
+    if let Err(e) = run(arg.action, arg.format) {
+        eprintln!("{e}");
+        std::process::exit(e.exit_code());
+    }
 }
\ No newline at end of file